@@ -1,23 +1,62 @@
-use crate::protocol::RespValue;
+use crate::connection::SharedWriter;
+use crate::protocol::{ProtocolVersion, RespValue};
+use crate::pubsub::{PubSub, Subscriber};
+use crate::spill::{self, SpillRefs};
+use std::path::PathBuf;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
 };
-use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// What's actually stored for a key: either the value itself, for anything
+/// that fit in memory, or the path of a spill file for a value so large that
+/// `RespValue::from_stream` streamed it straight to disk instead of
+/// buffering it (see `protocol::LARGE_VALUE_THRESHOLD`).
+#[derive(Debug, Clone)]
+pub enum StoredValue {
+    Inline(Vec<u8>),
+    Spilled(PathBuf),
+}
 
 /// each variant holds the arguments needed for that command
 #[derive(Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: Vec<u8> }, // uses Vec<u8> for binary safety
+    Set { key: String, value: StoredValue },
     Get { key: String },
     Del { key: String },
+    Subscribe { channels: Vec<String> },
+    Unsubscribe { channels: Vec<String> },
+    Publish { channel: String, message: Vec<u8> },
+    /// `HELLO [protover]`. `None` just re-reports the current handshake
+    /// without changing it, the same as real Redis.
+    Hello { version: Option<i64> },
     Quit,
     Unknown,
 }
 
+/// Deletes the spill file behind any `RespValue::Stream` in `array` other
+/// than the one at `adopted_index` (the position a command is actually
+/// taking ownership of, e.g. `SET`'s value). `from_stream` spills any
+/// oversized bulk string to disk purely based on its size, with no idea
+/// whether the position it's in can even accept one — a large `GET`/`DEL`
+/// key, `PUBLISH` channel/message, or `SUBSCRIBE` channel all parse to a
+/// `Stream` that no `Command` variant ever reads back out, so without this
+/// the temp file would never be removed.
+async fn cleanup_unadopted_streams(array: &[RespValue], adopted_index: Option<usize>) {
+    for (index, value) in array.iter().enumerate() {
+        if Some(index) == adopted_index {
+            continue;
+        }
+        if let RespValue::Stream(path) = value {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+}
+
 impl Command {
     // convert from the generic RESP protocol into our specific command types
-    pub fn parse_from_resp_array(array: Vec<RespValue>) -> Command {
+    pub async fn parse_from_resp_array(array: Vec<RespValue>) -> Command {
         if array.is_empty() {
             return Command::Unknown;
         }
@@ -28,19 +67,29 @@ impl Command {
         } else if let Some(RespValue::SimpleString(cmd_str)) = array.get(0) {
             cmd_str.to_ascii_uppercase()
         } else {
+            cleanup_unadopted_streams(&array, None).await;
             return Command::Unknown;
         };
 
-        match command_name.as_str() {
+        let command = match command_name.as_str() {
             "SET" => {
                 if array.len() >= 3 {
-                    if let (Some(RespValue::BulkString(key_bytes)), Some(RespValue::BulkString(value_bytes))) = (array.get(1), array.get(2)) {
+                    if let Some(RespValue::BulkString(key_bytes)) = array.get(1) {
                         let key = String::from_utf8_lossy(key_bytes).to_string();
                         // Using RESP the value is typically a single bulk string, this means we can just take the second argument as the value.
                         // if we wanted multi word values, the client would send them as a single bluk string
-                        Command::Set {
-                            key,
-                            value: value_bytes.clone(),
+                        match array.get(2) {
+                            Some(RespValue::BulkString(value_bytes)) => Command::Set {
+                                key,
+                                value: StoredValue::Inline(value_bytes.clone()),
+                            },
+                            // value was too large to buffer; from_stream already spilled
+                            // it to this path, so SET just takes ownership of the file
+                            Some(RespValue::Stream(path)) => Command::Set {
+                                key,
+                                value: StoredValue::Spilled(path.clone()),
+                            },
+                            _ => Command::Unknown, // malformed SET command arguments
                         }
                     } else {
                         Command::Unknown // malformed SET command arguments
@@ -73,24 +122,111 @@ impl Command {
                     Command::Unknown // wrong number of arguments
                 }
             }
+            "SUBSCRIBE" => {
+                let channels: Vec<String> = array[1..]
+                    .iter()
+                    .filter_map(|v| match v {
+                        RespValue::BulkString(bytes) => {
+                            Some(String::from_utf8_lossy(bytes).to_string())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                if channels.is_empty() {
+                    Command::Unknown // SUBSCRIBE requires at least one channel
+                } else {
+                    Command::Subscribe { channels }
+                }
+            }
+            "UNSUBSCRIBE" => {
+                let channels: Vec<String> = array[1..]
+                    .iter()
+                    .filter_map(|v| match v {
+                        RespValue::BulkString(bytes) => {
+                            Some(String::from_utf8_lossy(bytes).to_string())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                Command::Unsubscribe { channels }
+            }
+            "PUBLISH" => {
+                if array.len() == 3 {
+                    if let (Some(RespValue::BulkString(channel_bytes)), Some(RespValue::BulkString(message_bytes))) =
+                        (array.get(1), array.get(2))
+                    {
+                        Command::Publish {
+                            channel: String::from_utf8_lossy(channel_bytes).to_string(),
+                            message: message_bytes.clone(),
+                        }
+                    } else {
+                        Command::Unknown // malformed PUBLISH command arguments
+                    }
+                } else {
+                    Command::Unknown // wrong number of arguments
+                }
+            }
+            // HELLO [protover ...]; the toy server only cares about the
+            // protocol version, so AUTH/SETNAME and any other trailing
+            // arguments a real client sends are parsed but otherwise ignored
+            "HELLO" => match array.get(1) {
+                None => Command::Hello { version: None },
+                Some(RespValue::BulkString(bytes)) => {
+                    match String::from_utf8_lossy(bytes).parse::<i64>() {
+                        Ok(version) => Command::Hello { version: Some(version) },
+                        Err(_) => Command::Unknown,
+                    }
+                }
+                _ => Command::Unknown, // malformed HELLO command arguments
+            },
             "QUIT" => Command::Quit,
             _ => Command::Unknown,
-        }
+        };
+
+        // `SET key value` is the only place a parsed `Stream` is ever adopted
+        // (as the value to store); everywhere else one can appear is either
+        // a malformed command (oversized key/channel/message) or simply
+        // ignored by the match arms above, and either way `from_stream`
+        // already spilled it to a real file on disk that nothing else will
+        // ever clean up
+        let adopted_index = match &command {
+            Command::Set { value: StoredValue::Spilled(_), .. } => Some(2),
+            _ => None,
+        };
+        cleanup_unadopted_streams(&array, adopted_index).await;
+
+        command
     }
 
-    // executes the command and returns a "RespValue" as the response
+    // executes the command and returns a "RespValue" as the response.
+    // `pubsub` is the shared channel-subscriber registry and `socket` is this
+    // connection's own write half, so SUBSCRIBE can spawn a task that keeps
+    // pushing published messages to the client outside of this call.
     pub async fn execute(
         self,
-        db: Arc<Mutex<HashMap<String, Vec<u8>>>>, 
-        _socket: &mut (impl AsyncWriteExt + Unpin), // _socket is currently unused but might be useful for Pub/Sub
+        db: Arc<Mutex<HashMap<String, StoredValue>>>,
+        pubsub: PubSub,
+        spill_refs: SpillRefs,
+        socket: SharedWriter,
     ) -> Result<RespValue, Box<dyn std::error::Error + Send + Sync >> { // added Send + Sync for error handling across threads
         match self {
             Command::Set { key, value } => {
-                // acquire a lock on the Mutex
-                let mut db_locked = db
-                    .lock()
-                    .expect("Failed to acquire DB lock in SET command; Mutex might be poisoned");
-                db_locked.insert(key, value);
+                // acquire a lock on the Mutex just long enough to swap the
+                // pointer in; the value itself (however large) was already
+                // fully read off the wire before execute() was ever called
+                let old_value = {
+                    let mut db_locked = db
+                        .lock()
+                        .expect("Failed to acquire DB lock in SET command; Mutex might be poisoned");
+                    db_locked.insert(key, value)
+                };
+                // a spilled value this key used to point to is now orphaned;
+                // clean it up, unless a GET is still streaming it out, in
+                // which case `spill::delete` defers the unlink until that
+                // read finishes instead of corrupting it
+                if let Some(StoredValue::Spilled(old_path)) = old_value {
+                    spill::delete(&spill_refs, old_path).await;
+                }
                 Ok(RespValue::SimpleString("OK".to_string()))
             }
             Command::Get { key } => {
@@ -99,20 +235,186 @@ impl Command {
                     .lock()
                     .expect("Failed to acquire DB lock in GET command; Mutex might be poisoned");
                 match db_locked.get(&key) {
-                    Some(value) => Ok(RespValue::BulkString(value.clone())), // return as BulkString
+                    Some(StoredValue::Inline(value)) => Ok(RespValue::BulkString(value.clone())),
+                    // the writer streams this straight off disk instead of
+                    // buffering it; `acquire` registers the read while the DB
+                    // lock is still held, so a `DEL`/`SET`-overwrite that
+                    // takes the lock right after this sees it and defers its
+                    // delete instead of racing the file out from under us
+                    Some(StoredValue::Spilled(path)) => {
+                        spill::acquire(&spill_refs, path);
+                        Ok(RespValue::Stream(path.clone()))
+                    }
                     None => Ok(RespValue::Null), // Uuse Null for non-existent keys
                 }
             }
             Command::Del { key } => {
                 // acquire a lock
-                let mut db_locked = db
-                    .lock()
-                    .expect("Failed to acquire DB lock in DEL command; Mutex might be poisoned");
-                match db_locked.remove(&key) {
-                    Some(_) => Ok(RespValue::Integer(1)), // redis DEL returns number of keys deleted
+                let removed = {
+                    let mut db_locked = db
+                        .lock()
+                        .expect("Failed to acquire DB lock in DEL command; Mutex might be poisoned");
+                    db_locked.remove(&key)
+                };
+                match removed {
+                    Some(StoredValue::Spilled(path)) => {
+                        spill::delete(&spill_refs, path).await;
+                        Ok(RespValue::Integer(1))
+                    }
+                    Some(StoredValue::Inline(_)) => Ok(RespValue::Integer(1)), // redis DEL returns number of keys deleted
                     None => Ok(RespValue::Integer(0)), // 0 keys deleted
                 }
             }
+            Command::Subscribe { channels } => {
+                // one mpsc channel per SUBSCRIBE call: every channel it names
+                // gets a clone of the sender, and a single forwarding task
+                // drains the receiver until a write to the connection fails
+                // or all of those senders have left the registry
+                let (tx, mut rx) = mpsc::unbounded_channel::<RespValue>();
+                // identifies this connection's entries in the registry so
+                // UNSUBSCRIBE can later remove exactly them; stable for as
+                // long as this connection's `SharedWriter` is alive
+                let conn_id = Arc::as_ptr(&socket) as usize;
+                {
+                    let mut registry = pubsub
+                        .lock()
+                        .expect("Failed to acquire Pub/Sub registry lock in SUBSCRIBE command; Mutex might be poisoned");
+                    for channel in &channels {
+                        registry
+                            .entry(channel.clone())
+                            .or_insert_with(Vec::new)
+                            .push(Subscriber { conn_id, sender: tx.clone() });
+                    }
+                }
+
+                let writer = Arc::clone(&socket);
+                tokio::spawn(async move {
+                    while let Some(message) = rx.recv().await {
+                        let mut writer_locked = writer.lock().await;
+                        if writer_locked.write_message(&message).await.is_err() {
+                            break; // connection gone; let the task end quietly
+                        }
+                    }
+                });
+
+                Ok(RespValue::Array(
+                    channels
+                        .into_iter()
+                        .map(|channel| {
+                            RespValue::Array(vec![
+                                RespValue::BulkString(b"subscribe".to_vec()),
+                                RespValue::BulkString(channel.into_bytes()),
+                            ])
+                        })
+                        .collect(),
+                ))
+            }
+            Command::Unsubscribe { channels } => {
+                // this connection's own sender is never closed (its
+                // forwarding task is still holding the receiver), so
+                // unsubscribing has to remove it by identity: drop every
+                // `Subscriber` in the named channels whose `conn_id` matches
+                // this connection, not just ones whose receiver already
+                // dropped
+                let conn_id = Arc::as_ptr(&socket) as usize;
+                let mut registry = pubsub
+                    .lock()
+                    .expect("Failed to acquire Pub/Sub registry lock in UNSUBSCRIBE command; Mutex might be poisoned");
+                let mut replies = Vec::with_capacity(channels.len());
+                for channel in channels {
+                    if let Some(senders) = registry.get_mut(&channel) {
+                        senders.retain(|subscriber| subscriber.conn_id != conn_id);
+                        if senders.is_empty() {
+                            registry.remove(&channel);
+                        }
+                    }
+                    // how many channels this connection is still subscribed
+                    // to after this one, same as real Redis's UNSUBSCRIBE reply
+                    let remaining = registry
+                        .values()
+                        .filter(|senders| senders.iter().any(|subscriber| subscriber.conn_id == conn_id))
+                        .count();
+                    replies.push(RespValue::Array(vec![
+                        RespValue::BulkString(b"unsubscribe".to_vec()),
+                        RespValue::BulkString(channel.into_bytes()),
+                        RespValue::Integer(remaining as i64),
+                    ]));
+                }
+                Ok(RespValue::Array(replies))
+            }
+            Command::Publish { channel, message } => {
+                let mut registry = pubsub
+                    .lock()
+                    .expect("Failed to acquire Pub/Sub registry lock in PUBLISH command; Mutex might be poisoned");
+                let receiver_count = match registry.get_mut(&channel) {
+                    Some(senders) => {
+                        let payload = RespValue::Array(vec![
+                            RespValue::BulkString(b"message".to_vec()),
+                            RespValue::BulkString(channel.clone().into_bytes()),
+                            RespValue::BulkString(message.clone()),
+                        ]);
+                        // a failed send means that subscriber's connection is
+                        // gone (its forwarding task dropped the receiver) but
+                        // never ran UNSUBSCRIBE to say so; prune it here so
+                        // the registry doesn't grow unbounded over dead
+                        // connections
+                        senders.retain(|subscriber| subscriber.sender.send(payload.clone()).is_ok());
+                        let count = senders.len();
+                        if senders.is_empty() {
+                            registry.remove(&channel);
+                        }
+                        count
+                    }
+                    None => 0,
+                };
+                Ok(RespValue::Integer(receiver_count as i64))
+            }
+            Command::Hello { version } => {
+                let negotiated = match version {
+                    None => socket.lock().await.version(),
+                    Some(2) => ProtocolVersion::Resp2,
+                    Some(3) => ProtocolVersion::Resp3,
+                    Some(_) => {
+                        return Ok(RespValue::Error(
+                            "NOPROTO unsupported protocol version".to_string(),
+                        ))
+                    }
+                };
+                // takes effect starting with this very reply: the map below
+                // is serialized with whatever's in `negotiated`
+                socket.lock().await.set_version(negotiated);
+
+                let proto_number = match negotiated {
+                    ProtocolVersion::Resp2 => 2,
+                    ProtocolVersion::Resp3 => 3,
+                };
+                Ok(RespValue::Map(vec![
+                    (
+                        RespValue::BulkString(b"server".to_vec()),
+                        RespValue::BulkString(b"rusdis".to_vec()),
+                    ),
+                    (
+                        RespValue::BulkString(b"version".to_vec()),
+                        RespValue::BulkString(b"0.1.0".to_vec()),
+                    ),
+                    (
+                        RespValue::BulkString(b"proto".to_vec()),
+                        RespValue::Integer(proto_number),
+                    ),
+                    (
+                        RespValue::BulkString(b"mode".to_vec()),
+                        RespValue::BulkString(b"standalone".to_vec()),
+                    ),
+                    (
+                        RespValue::BulkString(b"role".to_vec()),
+                        RespValue::BulkString(b"master".to_vec()),
+                    ),
+                    (
+                        RespValue::BulkString(b"modules".to_vec()),
+                        RespValue::Array(vec![]),
+                    ),
+                ]))
+            }
             Command::Quit => {
                 Ok(RespValue::SimpleString("Connection closing shortly".to_string()))
             }
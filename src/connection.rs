@@ -0,0 +1,166 @@
+use crate::protocol::{self, ProtocolVersion, RespValue};
+use crate::relay::{self, RelaySink};
+use crate::transport::{EncryptedReadHalf, EncryptedStream, EncryptedWriteHalf};
+use crate::ws::{self, WsReadHalf, WsWriteHalf};
+use async_tungstenite::tokio::TokioAdapter;
+use async_tungstenite::WebSocketStream;
+use std::sync::Arc;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+
+/// A connection's wire transport before it has been split. RESP frames can
+/// arrive over a plaintext `TcpStream`, inside an `EncryptedStream` after an
+/// X25519 handshake, over a WebSocket, or demultiplexed off a relay's single
+/// outbound tunnel socket — `handle_client` and `Command::execute` don't need
+/// to know which.
+pub enum Conn {
+    Plain(TcpStream),
+    Encrypted(EncryptedStream<TcpStream>),
+    WebSocket(WebSocketStream<TokioAdapter<TcpStream>>),
+    /// One relayed client connection, demultiplexed by `relay::run_relay_client`:
+    /// `inbound` carries this connection's raw bytes off the relay tunnel, and
+    /// `outbound` is the shared sink back onto that same tunnel.
+    Relayed {
+        conn_id: u64,
+        inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+        outbound: RelaySink,
+    },
+}
+
+impl Conn {
+    /// Splits the connection into a reader, kept by the per-connection
+    /// response loop, and a shared writer handle that can also be cloned
+    /// into a Pub/Sub forwarding task so it can push messages outside the
+    /// normal request/response cycle.
+    pub fn into_split(self) -> (ConnReader, SharedWriter) {
+        match self {
+            Conn::Plain(stream) => {
+                let (read_half, write_half) = stream.into_split();
+                (
+                    ConnReader::Plain(read_half),
+                    Arc::new(Mutex::new(ConnWriter::new(ConnWriterTransport::Plain(write_half)))),
+                )
+            }
+            Conn::Encrypted(stream) => {
+                let (read_half, write_half) = stream.into_split();
+                (
+                    ConnReader::Encrypted(read_half),
+                    Arc::new(Mutex::new(ConnWriter::new(ConnWriterTransport::Encrypted(write_half)))),
+                )
+            }
+            Conn::WebSocket(stream) => {
+                let (read_half, write_half) = ws::split(stream);
+                (
+                    ConnReader::WebSocket(read_half),
+                    Arc::new(Mutex::new(ConnWriter::new(ConnWriterTransport::WebSocket(write_half)))),
+                )
+            }
+            Conn::Relayed {
+                conn_id,
+                inbound,
+                outbound,
+            } => (
+                ConnReader::Relayed {
+                    inbound,
+                    buffer: Vec::new(),
+                },
+                Arc::new(Mutex::new(ConnWriter::new(ConnWriterTransport::Relayed {
+                    conn_id,
+                    outbound,
+                }))),
+            ),
+        }
+    }
+}
+
+pub enum ConnReader {
+    Plain(OwnedReadHalf),
+    Encrypted(EncryptedReadHalf<ReadHalf<TcpStream>>),
+    WebSocket(WsReadHalf<TcpStream>),
+    Relayed {
+        inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+        buffer: Vec<u8>,
+    },
+}
+
+impl ConnReader {
+    pub async fn read_message(
+        &mut self,
+    ) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            ConnReader::Plain(half) => RespValue::from_stream(half).await,
+            ConnReader::Encrypted(half) => half.read_message().await,
+            ConnReader::WebSocket(half) => half.read_message().await,
+            ConnReader::Relayed { inbound, buffer } => {
+                protocol::from_buffered_frames(buffer, || async { Ok(inbound.recv().await) })
+                    .await
+            }
+        }
+    }
+}
+
+enum ConnWriterTransport {
+    Plain(OwnedWriteHalf),
+    Encrypted(EncryptedWriteHalf<WriteHalf<TcpStream>>),
+    WebSocket(WsWriteHalf<TcpStream>),
+    Relayed { conn_id: u64, outbound: RelaySink },
+}
+
+/// A connection's write half, paired with the RESP protocol version it has
+/// negotiated via `HELLO`. Every connection starts in RESP2 and stays there
+/// until a `HELLO 3` switches it, at which point RESP3-only response types
+/// (see `RespValue::to_bytes_for_version`) start going out in their native
+/// form instead of being downgraded.
+pub struct ConnWriter {
+    transport: ConnWriterTransport,
+    version: ProtocolVersion,
+}
+
+impl ConnWriter {
+    fn new(transport: ConnWriterTransport) -> Self {
+        ConnWriter {
+            transport,
+            version: ProtocolVersion::Resp2,
+        }
+    }
+
+    /// The protocol version this connection has negotiated so far.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Records a newly negotiated protocol version; `Command::Hello` calls
+    /// this after a successful `HELLO`. Takes effect for every response
+    /// written after this point, including ones already in flight for
+    /// earlier pipelined requests.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    pub async fn write_message(
+        &mut self,
+        value: &RespValue,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let version = self.version;
+        match &mut self.transport {
+            // the only transport that can stream a large value straight off
+            // disk instead of buffering it; see `RespValue::write_streaming`
+            ConnWriterTransport::Plain(half) => value.write_streaming(half, version).await,
+            ConnWriterTransport::Encrypted(half) => {
+                half.write_message(&value.to_bytes_buffered_for_version(version).await?).await
+            }
+            ConnWriterTransport::WebSocket(half) => {
+                half.write_message(value.to_bytes_buffered_for_version(version).await?).await
+            }
+            ConnWriterTransport::Relayed { conn_id, outbound } => {
+                relay::send_data(outbound, *conn_id, &value.to_bytes_buffered_for_version(version).await?).await
+            }
+        }
+    }
+}
+
+/// Owned, cloneable handle to a connection's write half. Shared between the
+/// connection's own response loop and any Pub/Sub forwarding tasks it spawns.
+pub type SharedWriter = Arc<Mutex<ConnWriter>>;
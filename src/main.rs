@@ -1,147 +1,335 @@
+mod command;
+mod connection;
+mod protocol;
+mod pubsub;
+mod relay;
+mod spill;
+mod transport;
+mod ws;
+
+use command::{Command, StoredValue};
+use connection::Conn;
+use protocol::RespValue;
+use pubsub::PubSub;
+use spill::SpillRefs;
 use std::{
     collections::HashMap, // in memory key-value store
-    sync::{Arc, Mutex},   // Arc for shared ownership, Mutex for mutual exclusion
-};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt}, // Asynchronous read and write operations on streams
-    net::{TcpListener, TcpStream},     // TcpListener to accept incoming connections, TcpStream for individual connections
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    }, // Arc for shared ownership, Mutex for mutual exclusion
 };
+use tokio::net::TcpListener; // TcpListener to accept incoming connections
+use tokio::sync::{watch, Notify};
+use transport::EncryptedStream;
+
+pub type Db = Arc<Mutex<HashMap<String, StoredValue>>>;
+
+/// Broadcasts process-wide shutdown to every accept loop and connection task.
+/// Waiting for in-flight connections to finish before the process exits is
+/// done separately, through `ConnTracker`.
+pub type Shutdown = watch::Receiver<bool>;
 
 #[tokio::main] // mark this as the main function for a tokio runtime
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // create in memory HashMap to store key-value pairs with arc<mutex for safe, shared and mutable access to it
-    let db: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    let db: Db = Arc::new(Mutex::new(HashMap::new()));
+
+    // shared registry of channel subscribers, for SUBSCRIBE/PUBLISH
+    let pubsub: PubSub = pubsub::new_registry();
+
+    // shared registry of in-flight spill-file reads, so GET streaming a
+    // large value can't be corrupted by a concurrent DEL/SET-overwrite of
+    // the same key
+    let spill_refs: SpillRefs = spill::new_registry();
+
+    // plaintext RESP listener, for local/trusted use
+    let plain_listener = TcpListener::bind("127.0.0.1:6379").await?;
+    println!("Server listening on 127.0.0.1:6379 (plaintext)");
+
+    // encrypted RESP listener: same protocol, wrapped behind an X25519 handshake
+    // and AES-256-GCM framing, for clients talking over an untrusted network
+    let encrypted_listener = TcpListener::bind("127.0.0.1:6380").await?;
+    println!("Server listening on 127.0.0.1:6380 (encrypted)");
+
+    // WebSocket listener, for browser-based or otherwise firewalled clients
+    let ws_listener = TcpListener::bind("127.0.0.1:6381").await?;
+    println!("Server listening on 127.0.0.1:6381 (websocket)");
+
+    // `false` means "keep running"; flipped to `true` once SIGINT/SIGTERM is
+    // received. Every accept loop and connection task holds a clone of the
+    // receiver so they all observe the signal at once.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        println!("Shutdown signal received; no longer accepting new connections...");
+        let _ = shutdown_tx.send(true);
+    });
+
+    let tracker = ConnTracker::new();
+
+    let plain_task = tokio::spawn(accept_loop(
+        plain_listener,
+        Arc::clone(&db),
+        Arc::clone(&pubsub),
+        Arc::clone(&spill_refs),
+        ListenerKind::Plain,
+        shutdown_rx.clone(),
+        Arc::clone(&tracker),
+    ));
+    let encrypted_task = tokio::spawn(accept_loop(
+        encrypted_listener,
+        Arc::clone(&db),
+        Arc::clone(&pubsub),
+        Arc::clone(&spill_refs),
+        ListenerKind::Encrypted,
+        shutdown_rx.clone(),
+        Arc::clone(&tracker),
+    ));
+    let ws_task = tokio::spawn(accept_loop(
+        ws_listener,
+        Arc::clone(&db),
+        Arc::clone(&pubsub),
+        Arc::clone(&spill_refs),
+        ListenerKind::WebSocket,
+        shutdown_rx.clone(),
+        Arc::clone(&tracker),
+    ));
+
+    // relay mode: alongside accepting locally, dial out to a
+    // public relay and serve whatever connections it tunnels back to us. Opt
+    // in by setting RUSDIS_RELAY_DOMAIN to the relay's base domain.
+    if let Ok(base_domain) = std::env::var("RUSDIS_RELAY_DOMAIN") {
+        let relay_db = Arc::clone(&db);
+        let relay_pubsub = Arc::clone(&pubsub);
+        let relay_spill_refs = Arc::clone(&spill_refs);
+        let relay_shutdown = shutdown_rx.clone();
+        let relay_tracker = Arc::clone(&tracker);
+        tokio::spawn(async move {
+            if let Err(e) = relay::run_relay_client(
+                base_domain,
+                relay_db,
+                relay_pubsub,
+                relay_spill_refs,
+                relay_shutdown,
+                relay_tracker,
+            )
+            .await
+            {
+                eprintln!("Relay connection failed: {}", e);
+            }
+        });
+    }
+
+    // run all local listeners until shutdown stops them (or one errors out)
+    tokio::try_join!(plain_task, encrypted_task, ws_task)?;
 
-    // bind the TcpListener to local address with default redis port
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-    println!("Server listening on 127.0.0.1:6379");
+    // listeners have stopped accepting; wait for whatever connections were
+    // already in flight to finish their current command and flush it
+    tracker.wait_idle().await;
+    println!("All connections drained; shutting down.");
 
-    // loop to accept client connections
+    Ok(())
+}
+
+/// Resolves once the process receives SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Tracks how many connection tasks are currently alive so shutdown can wait
+/// for them to drain instead of cutting them off mid-command.
+pub struct ConnTracker {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl ConnTracker {
+    fn new() -> Arc<Self> {
+        Arc::new(ConnTracker {
+            count: AtomicUsize::new(0),
+            idle: Notify::new(),
+        })
+    }
+
+    pub(crate) fn connection_started(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn connection_finished(&self) {
+        if self.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            // register for the next notification *before* re-checking the
+            // count: `notify_waiters` only wakes waiters already registered,
+            // so checking count first and awaiting `notified()` second would
+            // miss the wakeup if the last connection finished in between and
+            // this task would then wait forever
+            let notified = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Which handshake, if any, a listener's accepted connections go through
+/// before RESP commands can be parsed.
+#[derive(Clone, Copy)]
+enum ListenerKind {
+    Plain,
+    Encrypted,
+    WebSocket,
+}
+
+/// Accepts connections from `listener` until `shutdown` fires, spawning one
+/// task per client. Each spawned task registers itself with `tracker` so
+/// `main` can wait for it to drain before the process actually exits.
+async fn accept_loop(
+    listener: TcpListener,
+    db: Db,
+    pubsub: PubSub,
+    spill_refs: SpillRefs,
+    kind: ListenerKind,
+    mut shutdown: Shutdown,
+    tracker: Arc<ConnTracker>,
+) -> Result<(), Box<dyn std::error::Error>> {
     loop {
-        // wait for a new client connection.
-        // `accept()` returns a `TcpStream` and a `SocketAddr` 
-        let (mut socket, addr) = listener.accept().await?;
+        // wait for either a new client connection or the shutdown signal;
+        // once shutdown fires this loop stops accepting and returns, but
+        // already-spawned client tasks keep running until they drain
+        let (socket, addr) = tokio::select! {
+            biased;
+            _ = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    return Ok(());
+                }
+                continue;
+            }
+            accepted = listener.accept() => accepted?,
+        };
         println!("Accepted connection from: {}", addr);
 
-        // clone Arc to give each new task its own reference to the shared HashMap
+        // clone Arc to give each new task its own reference to the shared state
         let db_clone = Arc::clone(&db);
+        let pubsub_clone = Arc::clone(&pubsub);
+        let spill_refs_clone = Arc::clone(&spill_refs);
+        let shutdown_clone = shutdown.clone();
+        let tracker_clone = Arc::clone(&tracker);
 
+        tracker.connection_started();
         // start a new asynchronous task for each incoming client conn
         tokio::spawn(async move {
-            // call the client handler function.
-            if let Err(e) = handle_client(&mut socket, db_clone).await {
+            let conn = match kind {
+                ListenerKind::Plain => Conn::Plain(socket),
+                ListenerKind::Encrypted => match EncryptedStream::handshake(socket).await {
+                    Ok(stream) => Conn::Encrypted(stream),
+                    Err(e) => {
+                        eprintln!("Encrypted handshake failed for {}: {}", addr, e);
+                        tracker_clone.connection_finished();
+                        return;
+                    }
+                },
+                ListenerKind::WebSocket => {
+                    match async_tungstenite::tokio::accept_async(socket).await {
+                        Ok(stream) => Conn::WebSocket(stream),
+                        Err(e) => {
+                            eprintln!("WebSocket upgrade failed for {}: {}", addr, e);
+                            tracker_clone.connection_finished();
+                            return;
+                        }
+                    }
+                }
+            };
+
+            if let Err(e) =
+                handle_client(conn, db_clone, pubsub_clone, spill_refs_clone, shutdown_clone).await
+            {
                 eprintln!("Error handling client {}: {}", addr, e);
             }
             println!("Client disconnected: {}", addr);
+            tracker_clone.connection_finished();
         });
     }
 }
 
-/// handles a single client conn, reads commands from client, processes them and responds
-async fn handle_client(
-    socket: &mut TcpStream,
-    db: Arc<Mutex<HashMap<String, String>>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // buffer to read incoming data from the client
-    // read up to 1024 bytes at a time
-    let mut buf = vec![0; 1024];
+/// handles a single client conn: reads RESP commands (through whichever
+/// transport `conn` wraps), executes them against the shared store, and
+/// writes back the RESP response. The connection is split into a reader
+/// kept here and a shared writer handle, so commands like SUBSCRIBE can
+/// clone the writer into their own task and push messages independently
+/// of this request/response loop.
+///
+/// Each iteration races reading the next command against `shutdown`. Once
+/// shutdown fires, the loop stops waiting for new commands and returns, but
+/// a command already read out of the socket always runs to completion and
+/// has its response flushed first — the client never sees its last request
+/// silently dropped.
+pub async fn handle_client(
+    conn: Conn,
+    db: Db,
+    pubsub: PubSub,
+    spill_refs: SpillRefs,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut reader, writer) = conn.into_split();
 
-    // loop indefinitely to read commands
     loop {
-        // read bytes from the TCP stream into the buffer
-        // "read" returns the number of bytes read. If 0, the client has disconnected
-        let n = socket.read(&mut buf).await?;
-
-        if n == 0 {
-            // client disconnected
-            return Ok(());
-        }
-
-        // convert the received bytes into a string assuming UTF.8 input
-        let command_str = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-        println!("Received command: '{}'", command_str);
-
-        // simple parsing
-        let parts: Vec<&str> = command_str.split_whitespace().collect();
-
-        // initialize a response string
-        let response: String;
-
-        // match 1st split to available commands
-        match parts.get(0).map(|s| s.to_ascii_uppercase()) {
-            Some(cmd) => {
-                match cmd.as_str(){                
-                    "SET" => {
-                        // handle set command: Set key value
-                        if parts.len() >= 3 {
-                            let key = parts[1].to_string();
-                            // join remaining parts after key parts(1) as value possibly separated by spaces 
-                            let value = parts[2..].join(" ");
-
-                            // acquire a lock on the Mutex to safely access the HashMap
-                            // this blocks other threads/tasks from writing to the HashMap until lock is released
-                            let mut db_locked = db.lock().unwrap(); // `unwrap()` is "safe" here for simplicity
-                            db_locked.insert(key, value);
-                            response = "OK\n".to_string();
-                        } else {
-                            response = "ERR wrong number of arguments for 'SET' command\n".to_string();
-                        }
-                    }
-                    "GET" => {
-                        // handle get command: GET key
-                        if parts.len() == 2 {
-                            let key = parts[1].to_string();
-
-                            // acquire lock again
-                            let db_locked = db.lock().unwrap();
-                            response = match db_locked.get(&key) {
-                                Some(value) => format!("{}\n", value), // key found return value
-                                None => "(nil)\n".to_string(),        // key not found return nil
-                            };
-                        } else {
-                            response = "ERR wrong number of arguments for 'GET' command\n".to_string();
-                        }
-                    }
-                    "DEL" => {
-                        // handle del command: DEL key
-                        if parts.len() == 2 {
-                            let key = parts[1].to_string();
-
-                            // acquire lock
-                            let mut db_locked = db.lock().unwrap();
-                            response = match db_locked.remove(&key) {
-                                Some(_) => "OK\n".to_string(), // key exists and is removed
-                                None => "(nil)\n".to_string(), // key not found
-                            };
-                        } else {
-                            response = "ERR wrong number of arguments for 'DEL' command\n".to_string();
-                        }
-                    }
-                    "QUIT" => {
-                        // quit command to close the conn
-                        response = "Connection will be closed shortly!\n".to_string();
-                        socket.write_all(response.as_bytes()).await?;
-                        return Ok(()); // exit the loop and terminate the client handler task
-                    }
-                    "LIST" => {
-                        // list command to list available commands
-                        response = "Available commands are:\n SET <key> <value>\n GET <key>\n DEL <key>\n QUIT (to close connection)\n".to_string();
-                    }
-                    _ => {
-                        // handle unknown commands
-                        response = "ERR unknown command\n Use LIST to see available commands\n".to_string();
-                    }
+        let request = tokio::select! {
+            biased;
+            _ = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    return Ok(());
                 }
-            },
-            None => {
-                // handle empty command string
-                response = "ERR empty command\n".to_string();
+                continue;
             }
+            message = reader.read_message() => match message? {
+                Some(RespValue::Array(elements)) => Command::parse_from_resp_array(elements).await,
+                Some(_) => Command::Unknown,
+                None => return Ok(()), // client disconnected
+            },
+        };
+
+        let is_quit = matches!(request, Command::Quit);
+
+        let response = request
+            .execute(Arc::clone(&db), Arc::clone(&pubsub), Arc::clone(&spill_refs), Arc::clone(&writer))
+            .await?;
+
+        // a `GET` response streaming a spill file registered itself with
+        // `spill_refs` while building `response`; release that registration
+        // once the write below is done with the file, win or lose, so a
+        // `DEL`/`SET`-overwrite that deferred its delete isn't stuck waiting
+        // on a read that already finished (or failed)
+        let spilled_path = match &response {
+            RespValue::Stream(path) => Some(path.clone()),
+            _ => None,
+        };
+        let write_result = writer.lock().await.write_message(&response).await;
+        if let Some(path) = spilled_path {
+            spill::release(&spill_refs, &path).await;
         }
-            
+        write_result?;
 
-        // write generated response back to the client
-        socket.write_all(response.as_bytes()).await?;
+        if is_quit {
+            return Ok(());
+        }
     }
 }
-
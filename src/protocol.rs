@@ -1,4 +1,24 @@
-use tokio::io::AsyncReadExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Bulk strings declared longer than this are not buffered whole in RAM by
+/// `from_stream`, which spills them to a temp file in `CHUNK_SIZE`-sized reads
+/// instead. `from_stream_buffered` has no such limit and buffers them whole.
+pub const LARGE_VALUE_THRESHOLD: usize = 64 * 1024;
+
+/// Chunk size used both for spilling an oversized incoming bulk string to
+/// disk and for streaming one back out.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Which RESP protocol version a connection has negotiated via `HELLO`.
+/// Connections start out in RESP2 until a client opts into RESP3; see
+/// `Command::Hello` and `ConnWriter::set_version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    Resp2,
+    Resp3,
+}
 
 // represent the different types of values whitin RESP
 #[derive(Debug, PartialEq, Clone)]
@@ -9,6 +29,34 @@ pub enum RespValue {
     BulkString(Vec<u8>),
     Array(Vec<RespValue>),
     Null, // represents a null bulk string ($-1\r\n) or null array
+    /// A bulk string too large to buffer in memory. Read side: `from_stream`
+    /// already streamed its payload out to this temp file in bounded chunks
+    /// as it parsed the frame. Write side: `Command::Get` returns this for a
+    /// key whose value lives on disk. The plaintext TCP writer streams the
+    /// file's contents back out as the bulk string payload (see
+    /// `write_streaming`) instead of calling `to_bytes` (which would have to
+    /// buffer it whole); the encrypted, WebSocket and relay writers do not
+    /// stream it and read the whole file into memory first (see
+    /// `to_bytes_buffered_for_version`).
+    Stream(PathBuf),
+    /// RESP3 map: an ordered list of key/value pairs, e.g. `HELLO`'s reply.
+    /// Serialized natively for RESP3 connections; downgraded to a flat
+    /// `Array` of alternating keys and values for RESP2 ones, same as real
+    /// Redis does. See `to_bytes_for_version`.
+    Map(Vec<(RespValue, RespValue)>),
+    /// RESP3 double. Downgraded to a `BulkString` of its formatted value for
+    /// RESP2 connections.
+    Double(f64),
+    /// RESP3 boolean. Downgraded to `Integer(1)`/`Integer(0)` for RESP2.
+    Boolean(bool),
+    /// RESP3 big number, carried as its decimal string form (RESP has no
+    /// native integer type wide enough for it). Downgraded to a `BulkString`
+    /// of that same string for RESP2.
+    BigNumber(String),
+    /// RESP3 verbatim string: a three-character format tag (e.g. `"txt"`)
+    /// plus the text itself. Downgraded to a plain `BulkString` of the text
+    /// for RESP2, dropping the format tag.
+    Verbatim(String, String),
 }
 
 impl RespValue {
@@ -34,12 +82,160 @@ impl RespValue {
                 bytes
             }
             RespValue::Null => b"$-1\r\n".to_vec(),
+            RespValue::Stream(_) => unreachable!(
+                "RespValue::Stream can't be serialized in memory; transports must serialize it via write_streaming or to_bytes_buffered_for_version instead of to_bytes"
+            ),
+            RespValue::Map(pairs) => {
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                for (key, value) in pairs {
+                    bytes.extend_from_slice(&key.to_bytes());
+                    bytes.extend_from_slice(&value.to_bytes());
+                }
+                bytes
+            }
+            RespValue::Double(d) => format!(",{}\r\n", format_double(*d)).into_bytes(),
+            RespValue::Boolean(b) => if *b { b"#t\r\n".to_vec() } else { b"#f\r\n".to_vec() },
+            RespValue::BigNumber(s) => format!("({}\r\n", s).into_bytes(),
+            RespValue::Verbatim(format_tag, text) => {
+                let payload = format!("{}:{}", format_tag, text);
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(format!("={}\r\n", payload.len()).as_bytes());
+                bytes.extend_from_slice(payload.as_bytes());
+                bytes.extend_from_slice(b"\r\n");
+                bytes
+            }
+        }
+    }
+
+    /// Like `to_bytes`, but serializes the way a connection that negotiated
+    /// `version` via `HELLO` expects: RESP3-only types downgrade to their
+    /// closest RESP2 equivalent for a RESP2 connection (a `Map` becomes a
+    /// flat `Array`, a `Double` a `BulkString`, and so on), recursing into
+    /// `Array`/`Map` elements so a downgrade doesn't stop at the top level.
+    /// Every other variant serializes identically in both versions.
+    pub fn to_bytes_for_version(&self, version: ProtocolVersion) -> Vec<u8> {
+        match (self, version) {
+            (RespValue::Map(pairs), ProtocolVersion::Resp2) => {
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                for (key, value) in pairs {
+                    bytes.extend_from_slice(&key.to_bytes_for_version(version));
+                    bytes.extend_from_slice(&value.to_bytes_for_version(version));
+                }
+                bytes
+            }
+            (RespValue::Map(pairs), ProtocolVersion::Resp3) => {
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                for (key, value) in pairs {
+                    bytes.extend_from_slice(&key.to_bytes_for_version(version));
+                    bytes.extend_from_slice(&value.to_bytes_for_version(version));
+                }
+                bytes
+            }
+            (RespValue::Double(d), ProtocolVersion::Resp2) => {
+                RespValue::BulkString(format_double(*d).into_bytes()).to_bytes()
+            }
+            (RespValue::Boolean(b), ProtocolVersion::Resp2) => {
+                RespValue::Integer(if *b { 1 } else { 0 }).to_bytes()
+            }
+            (RespValue::BigNumber(s), ProtocolVersion::Resp2) => {
+                RespValue::BulkString(s.clone().into_bytes()).to_bytes()
+            }
+            (RespValue::Verbatim(_, text), ProtocolVersion::Resp2) => {
+                RespValue::BulkString(text.clone().into_bytes()).to_bytes()
+            }
+            (RespValue::Array(items), _) => {
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    bytes.extend_from_slice(&item.to_bytes_for_version(version));
+                }
+                bytes
+            }
+            _ => self.to_bytes(),
+        }
+    }
+
+    /// Like `to_bytes_for_version`, but buffers a `Stream` value's file into
+    /// memory first instead of panicking. For transports that frame a whole
+    /// message at once (encrypted, WebSocket, relay tunnel) there's no way to
+    /// stream a value progressively within a single frame, so they fall back
+    /// to this instead of `write_streaming`, trading the memory-saving
+    /// benefit of `Stream` for staying within their existing per-frame design.
+    pub async fn to_bytes_buffered_for_version(
+        &self,
+        version: ProtocolVersion,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            RespValue::Stream(path) => Ok(RespValue::BulkString(tokio::fs::read(path).await?).to_bytes()),
+            other => Ok(other.to_bytes_for_version(version)),
+        }
+    }
+
+    /// Writes this value to `out` the same way `to_bytes_for_version` would,
+    /// except a `Stream` payload is read off disk and written in bounded
+    /// chunks instead of being buffered whole. This is the only way to
+    /// serialize a `Stream` value without buffering it whole
+    /// (`to_bytes_buffered_for_version` reads the file into memory); every
+    /// other variant just writes
+    /// `to_bytes_for_version(version)` as-is.
+    pub async fn write_streaming(
+        &self,
+        out: &mut (impl tokio::io::AsyncWrite + Unpin),
+        version: ProtocolVersion,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            RespValue::Stream(path) => {
+                let len = tokio::fs::metadata(path).await?.len() as usize;
+                out.write_all(format!("${}\r\n", len).as_bytes()).await?;
+
+                let mut file = tokio::fs::File::open(path).await?;
+                let mut buf = vec![0u8; CHUNK_SIZE];
+                let mut remaining = len;
+                while remaining > 0 {
+                    let take = remaining.min(CHUNK_SIZE);
+                    file.read_exact(&mut buf[..take]).await?;
+                    out.write_all(&buf[..take]).await?;
+                    remaining -= take;
+                }
+                out.write_all(b"\r\n").await?;
+                Ok(())
+            }
+            other => {
+                out.write_all(&other.to_bytes_for_version(version)).await?;
+                Ok(())
+            }
         }
     }
 
     // attempt to parse bytes from an `AsyncRead` (like a `TcpStream`) into a `RespValue`.
     // Simplified parser implementation
     pub async fn from_stream(stream: &mut (impl AsyncReadExt + Unpin)) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync >> { // added Send + Sync for error handling across threads
+        Self::from_stream_impl(stream, true).await
+    }
+
+    /// Like `from_stream`, but never spills an oversized bulk string to a
+    /// temp file. `from_buffered_frames` parses off a cursor over a buffer
+    /// that's refilled and re-parsed *from the start* on every incomplete
+    /// attempt (message-oriented transports have no other way to resume a
+    /// partial parse), so a spill-to-disk branch there would create a fresh
+    /// spill file and rewrite it from scratch on every retry. Buffering the
+    /// value in memory instead is wasteful for a single giant value, but
+    /// it's what these transports already do for every response anyway (see
+    /// `to_bytes_buffered_for_version`), so it costs nothing extra and isn't
+    /// vulnerable to repeated re-parsing.
+    pub(crate) async fn from_stream_buffered(
+        stream: &mut (impl AsyncReadExt + Unpin),
+    ) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
+        Self::from_stream_impl(stream, false).await
+    }
+
+    async fn from_stream_impl(
+        stream: &mut (impl AsyncReadExt + Unpin),
+        allow_spill: bool,
+    ) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
         let mut buf = Vec::new();
         let mut temp_byte = [0; 1];
 
@@ -73,6 +269,15 @@ impl RespValue {
                 let len: i64 = line.parse()?;
                 if len == -1 {
                     Ok(Some(RespValue::Null))
+                } else if allow_spill && len as usize > LARGE_VALUE_THRESHOLD {
+                    // too big to buffer whole: stream it straight to a spill
+                    // file in bounded chunks and hand back just the path.
+                    let path = spill_file_path();
+                    let mut file = tokio::fs::File::create(&path).await?;
+                    read_bulk_string_chunked(stream, len as usize, |chunk| file.write_all(chunk)).await?;
+                    // read the trailing CRLF after the bulk string
+                    stream.read_exact(&mut [0; 2]).await?;
+                    Ok(Some(RespValue::Stream(path)))
                 } else {
                     let mut data_buf = vec![0; len as usize];
                     stream.read_exact(&mut data_buf).await?;
@@ -88,7 +293,9 @@ impl RespValue {
                 } else {
                     let mut elements = Vec::with_capacity(num_elements as usize);
                     for _ in 0..num_elements {
-                        if let Some(element) = Box::pin(RespValue::from_stream(stream)).await? {
+                        if let Some(element) =
+                            Box::pin(RespValue::from_stream_impl(stream, allow_spill)).await?
+                        {
                             elements.push(element);
                         } else {
                             return Err("Unexpected end of stream while reading array elements".into());
@@ -97,7 +304,159 @@ impl RespValue {
                     Ok(Some(RespValue::Array(elements)))
                 }
             }
+            b'%' => {
+                let num_pairs: i64 = line.parse()?;
+                let mut pairs = Vec::with_capacity(num_pairs as usize);
+                for _ in 0..num_pairs {
+                    let key = Box::pin(RespValue::from_stream_impl(stream, allow_spill))
+                        .await?
+                        .ok_or("Unexpected end of stream while reading map key")?;
+                    let value = Box::pin(RespValue::from_stream_impl(stream, allow_spill))
+                        .await?
+                        .ok_or("Unexpected end of stream while reading map value")?;
+                    pairs.push((key, value));
+                }
+                Ok(Some(RespValue::Map(pairs)))
+            }
+            b',' => {
+                let value = match line.as_str() {
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    "nan" => f64::NAN,
+                    other => other.parse()?,
+                };
+                Ok(Some(RespValue::Double(value)))
+            }
+            b'#' => match line.as_str() {
+                "t" => Ok(Some(RespValue::Boolean(true))),
+                "f" => Ok(Some(RespValue::Boolean(false))),
+                _ => Err(format!("Invalid RESP3 boolean: {}", line).into()),
+            },
+            b'(' => Ok(Some(RespValue::BigNumber(line))),
+            b'=' => {
+                let len: i64 = line.parse()?;
+                let mut data_buf = vec![0; len as usize];
+                stream.read_exact(&mut data_buf).await?;
+                stream.read_exact(&mut [0; 2]).await?;
+                let payload = String::from_utf8(data_buf)?;
+                match payload.split_once(':') {
+                    Some((format_tag, text)) => {
+                        Ok(Some(RespValue::Verbatim(format_tag.to_string(), text.to_string())))
+                    }
+                    None => Err("Malformed RESP3 verbatim string: missing format tag".into()),
+                }
+            }
             _ => Err(format!("Unknown RESP prefix: {}", prefix as char).into()),
         }
     }
+}
+
+/// Parses one `RespValue` out of `buffer`, pulling in more bytes via
+/// `fetch_more` whenever the buffer doesn't yet hold a complete message.
+///
+/// This is for message-oriented transports (WebSocket frames, relay tunnel
+/// frames) where RESP bytes don't arrive as a plain byte stream: a single
+/// incoming message may hold zero, one, or several RESP values, and a value
+/// may be split across messages. `from_stream` itself only knows how to read
+/// from a byte stream, so here we hand it a slice cursor over `buffer` and,
+/// if it runs out of bytes mid-parse, fetch another chunk and retry from the
+/// start of the (now larger) buffer. Bytes the parse actually consumed are
+/// drained so any leftover already-buffered value stays put for next time.
+pub async fn from_buffered_frames<F, Fut>(
+    buffer: &mut Vec<u8>,
+    mut fetch_more: F,
+) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    loop {
+        {
+            let mut cursor: &[u8] = &buffer[..];
+            match RespValue::from_stream_buffered(&mut cursor).await {
+                Ok(Some(value)) => {
+                    let consumed = buffer.len() - cursor.len();
+                    buffer.drain(0..consumed);
+                    return Ok(Some(value));
+                }
+                Ok(None) => {}
+                Err(e) if is_incomplete(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match fetch_more().await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Whether `e` indicates the cursor simply ran out of buffered bytes
+/// mid-parse, as opposed to the message actually being malformed.
+fn is_incomplete(e: &Box<dyn std::error::Error + Send + Sync>) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .map(|io_err| io_err.kind() == std::io::ErrorKind::UnexpectedEof)
+        .unwrap_or(false)
+}
+
+/// Reads a declared-length payload in bounded `CHUNK_SIZE` chunks, invoking
+/// `on_chunk` with each one instead of buffering the whole payload at once.
+pub async fn read_bulk_string_chunked<S, F, Fut>(
+    stream: &mut S,
+    len: usize,
+    mut on_chunk: F,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncReadExt + Unpin,
+    F: FnMut(&[u8]) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<()>>,
+{
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let take = remaining.min(CHUNK_SIZE);
+        stream.read_exact(&mut buf[..take]).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "premature end of stream with {} bytes left in bulk string",
+                        remaining
+                    ),
+                )
+            } else {
+                e
+            }
+        })?;
+        on_chunk(&buf[..take]).await?;
+        remaining -= take;
+    }
+    Ok(())
+}
+
+/// Formats a double the way RESP3 expects on the wire: the usual decimal
+/// form, except the non-finite cases spelled out as `inf`/`-inf`/`nan`
+/// instead of Rust's `f64` `Display` output.
+fn format_double(d: f64) -> String {
+    if d.is_infinite() {
+        if d > 0.0 {
+            "inf".to_string()
+        } else {
+            "-inf".to_string()
+        }
+    } else if d.is_nan() {
+        "nan".to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A fresh, unique path under the system temp dir for spilling one oversized
+/// bulk string while it's being parsed.
+fn spill_file_path() -> PathBuf {
+    let id = SPILL_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("rusdis-spill-{}-{}.tmp", std::process::id(), id))
 }
\ No newline at end of file
@@ -0,0 +1,27 @@
+use crate::protocol::RespValue;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// One connection's slot in a channel's subscriber list. `conn_id` is a
+/// stable per-connection identity (see `Command::execute`'s `socket`
+/// parameter) so `UNSUBSCRIBE` can find and remove exactly this connection's
+/// entry instead of any other subscriber's — a plain `Vec<UnboundedSender<_>>`
+/// can't tell two still-open senders apart.
+pub struct Subscriber {
+    pub conn_id: usize,
+    pub sender: UnboundedSender<RespValue>,
+}
+
+/// Registry of channel subscribers, shared across every connection alongside
+/// the key-value store. Each subscribed connection holds one sender per
+/// channel it's on; `PUBLISH` looks up a channel's senders and fans the
+/// message out to all of them.
+pub type PubSub = Arc<Mutex<HashMap<String, Vec<Subscriber>>>>;
+
+/// Creates an empty Pub/Sub registry, to be created alongside `db` in `main`.
+pub fn new_registry() -> PubSub {
+    Arc::new(Mutex::new(HashMap::new()))
+}
@@ -0,0 +1,149 @@
+use crate::connection::Conn;
+use crate::handle_client;
+use crate::{ConnTracker, Db, Shutdown};
+use crate::pubsub::PubSub;
+use crate::spill::SpillRefs;
+use async_tungstenite::tokio::{connect_async, TokioAdapter};
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+
+/// Handle to the single outbound WebSocket held open with the relay. Every
+/// relayed connection's `ConnWriter` shares one of these, tagged with its own
+/// `conn_id`, so outgoing bytes get multiplexed back onto the same socket.
+pub type RelaySink = Arc<Mutex<SplitSink<WebSocketStream<TokioAdapter<TcpStream>>, Message>>>;
+
+fn encode_frame(kind: u8, conn_id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + 8 + 4 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&conn_id.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn decode_frame(bytes: &[u8]) -> Result<(u8, u64, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    if bytes.len() < 13 {
+        return Err("relay frame shorter than its fixed header".into());
+    }
+    let kind = bytes[0];
+    let conn_id = u64::from_be_bytes(bytes[1..9].try_into()?);
+    let len = u32::from_be_bytes(bytes[9..13].try_into()?) as usize;
+    let payload = bytes.get(13..13 + len).ok_or("relay frame payload shorter than its declared length")?;
+    Ok((kind, conn_id, payload.to_vec()))
+}
+
+/// Sends one chunk of response bytes for `conn_id` back over the shared relay
+/// socket, framed as a `FRAME_DATA` message.
+pub async fn send_data(
+    sink: &RelaySink,
+    conn_id: u64,
+    payload: &[u8],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let frame = encode_frame(FRAME_DATA, conn_id, payload);
+    sink.lock().await.send(Message::Binary(frame)).await?;
+    Ok(())
+}
+
+/// Dials out to `base_domain`, registers for a public address, and then
+/// demultiplexes relayed client connections off that single socket until
+/// `shutdown` fires. Each relayed connection gets its own `handle_client`
+/// task, exactly like a normal accepted `TcpStream` would, and registers with
+/// `tracker` the same way too so shutdown waits for it to drain.
+pub async fn run_relay_client(
+    base_domain: String,
+    db: Db,
+    pubsub: PubSub,
+    spill_refs: SpillRefs,
+    mut shutdown: Shutdown,
+    tracker: Arc<ConnTracker>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("wss://{}/register", base_domain);
+    let (ws_stream, _response) = connect_async(url).await?;
+    let (sink, mut stream) = ws_stream.split();
+    let sink: RelaySink = Arc::new(Mutex::new(sink));
+
+    // the relay's first reply assigns us the public subdomain/ID clients will use
+    let assigned_id = match stream.next().await {
+        Some(Ok(Message::Text(id))) => id,
+        Some(Ok(_)) => return Err("relay did not assign a public address as its first message".into()),
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err("relay closed the registration socket immediately".into()),
+    };
+    println!("Public relay address: https://{}.{}", assigned_id, base_domain);
+
+    let mut open_conns: HashMap<u64, mpsc::UnboundedSender<Vec<u8>>> = HashMap::new();
+
+    loop {
+        let message = tokio::select! {
+            biased;
+            _ = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    return Ok(());
+                }
+                continue;
+            }
+            message = stream.next() => message,
+        };
+        let Some(message) = message else { break };
+        let bytes = match message? {
+            Message::Binary(bytes) => bytes,
+            Message::Close(_) => break,
+            _ => continue, // ignore text/ping/pong keepalives on the tunnel itself
+        };
+        let (kind, conn_id, payload) = decode_frame(&bytes)?;
+
+        match kind {
+            FRAME_OPEN => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                open_conns.insert(conn_id, tx);
+
+                let conn = Conn::Relayed {
+                    conn_id,
+                    inbound: rx,
+                    outbound: Arc::clone(&sink),
+                };
+                let db_clone = Arc::clone(&db);
+                let pubsub_clone = Arc::clone(&pubsub);
+                let spill_refs_clone = Arc::clone(&spill_refs);
+                let shutdown_clone = shutdown.clone();
+                let tracker_clone = Arc::clone(&tracker);
+                tracker.connection_started();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_client(
+                        conn,
+                        db_clone,
+                        pubsub_clone,
+                        spill_refs_clone,
+                        shutdown_clone,
+                    )
+                    .await
+                    {
+                        eprintln!("Error handling relayed client {}: {}", conn_id, e);
+                    }
+                    tracker_clone.connection_finished();
+                });
+            }
+            FRAME_DATA => {
+                if let Some(tx) = open_conns.get(&conn_id) {
+                    let _ = tx.send(payload);
+                }
+            }
+            FRAME_CLOSE => {
+                open_conns.remove(&conn_id);
+            }
+            _ => eprintln!("Unknown relay frame kind {} for connection {}", kind, conn_id),
+        }
+    }
+
+    Ok(())
+}
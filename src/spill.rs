@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// How many in-flight `GET` reads are currently streaming a spill file, and
+/// whether a `DEL`/`SET`-overwrite asked to delete it while one was.
+#[derive(Default)]
+struct SpillEntry {
+    readers: u32,
+    pending_delete: bool,
+}
+
+/// Tracks spill files that a `GET` is currently streaming out, so a
+/// concurrent `DEL` or `SET`-overwrite of the same key can't unlink the file
+/// out from under a read already in progress: instead of deleting it right
+/// away, the delete is deferred until the last in-flight reader releases it.
+/// Shared across every connection alongside `Db` and `PubSub`.
+pub type SpillRefs = Arc<Mutex<HashMap<PathBuf, SpillEntry>>>;
+
+/// Creates an empty spill-file registry, to be created alongside `db` in `main`.
+pub fn new_registry() -> SpillRefs {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+/// Registers one in-flight read of `path`; pairs with `release`. Must be
+/// called before the value handed back to the caller (a `RespValue::Stream`)
+/// can be observed by a concurrent `DEL`/`SET`, i.e. while still holding the
+/// `Db` lock that guarded the lookup.
+pub fn acquire(refs: &SpillRefs, path: &Path) {
+    let mut refs_locked = refs
+        .lock()
+        .expect("Failed to acquire spill-refs lock in acquire; Mutex might be poisoned");
+    refs_locked.entry(path.to_path_buf()).or_default().readers += 1;
+}
+
+/// Releases one read of `path` acquired via `acquire`. If a delete was
+/// deferred while this was the last reader, performs it now.
+pub async fn release(refs: &SpillRefs, path: &Path) {
+    let should_delete = {
+        let mut refs_locked = refs
+            .lock()
+            .expect("Failed to acquire spill-refs lock in release; Mutex might be poisoned");
+        match refs_locked.get_mut(path) {
+            Some(entry) => {
+                entry.readers -= 1;
+                if entry.readers == 0 {
+                    let pending = entry.pending_delete;
+                    refs_locked.remove(path);
+                    pending
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    };
+    if should_delete {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+/// Deletes `path`'s spill file, unless a `GET` is currently streaming it via
+/// `acquire`/`release`, in which case the delete is deferred until that last
+/// reader finishes instead of corrupting its read.
+pub async fn delete(refs: &SpillRefs, path: PathBuf) {
+    let should_delete_now = {
+        let mut refs_locked = refs
+            .lock()
+            .expect("Failed to acquire spill-refs lock in delete; Mutex might be poisoned");
+        match refs_locked.get_mut(&path) {
+            Some(entry) if entry.readers > 0 => {
+                entry.pending_delete = true;
+                false
+            }
+            _ => {
+                refs_locked.remove(&path);
+                true
+            }
+        }
+    };
+    if should_delete_now {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
@@ -0,0 +1,217 @@
+use crate::protocol::RespValue;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Domain-separation labels used to derive the two directional keys from the
+/// shared secret. Without these, both peers would land on the same key and
+/// both would start their nonce counter at 0, so frame #0 of one direction
+/// would reuse the (key, nonce) pair of frame #0 of the other.
+const KEY_LABEL_LOW_TO_HIGH: &[u8] = b"rusdis encrypted transport: low->high";
+const KEY_LABEL_HIGH_TO_LOW: &[u8] = b"rusdis encrypted transport: high->low";
+
+/// Wraps a byte stream (normally a `TcpStream`) in an encrypted framing layer.
+///
+/// Every `RespValue` sent over an `EncryptedStream` is serialized with
+/// `to_bytes`, sealed with AES-256-GCM, and written as one frame of
+/// `nonce (12 bytes) || ciphertext_len (4 bytes, big-endian) || ciphertext+tag`.
+/// The two directions never share a key: `handshake` orders the two public
+/// keys and derives one key for the "low public key -> high public key"
+/// direction and a different one for the reverse, so even though both sides
+/// start their write counter at 0, the (key, nonce) pair they produce is
+/// never reused by the other side. Within one direction the counter alone
+/// keeps nonces unique.
+pub struct EncryptedStream<S> {
+    inner: S,
+    read_cipher: Aes256Gcm,
+    write_cipher: Aes256Gcm,
+    write_counter: u64,
+}
+
+impl<S> EncryptedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs an ephemeral X25519 handshake over `inner` and derives a pair
+    /// of directional AES-256-GCM keys from the shared secret, then returns a
+    /// stream ready to carry encrypted RESP frames.
+    ///
+    /// Both sides run the same steps, so it doesn't matter whether this is
+    /// called from the accept side or the connect side: generate a keypair,
+    /// exchange the 32-byte public keys in the clear, and hash the shared
+    /// secret through SHA-256 (with a direction label) to get the two keys.
+    /// The two public keys are compared byte-for-byte to agree, without any
+    /// extra handshake round-trip, on which peer is "low" and which is
+    /// "high" so each side picks the matching read/write key out of the
+    /// pair.
+    pub async fn handshake(
+        mut inner: S,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+        let our_public = PublicKey::from(&secret);
+
+        inner.write_all(our_public.as_bytes()).await?;
+        inner.flush().await?;
+
+        let mut their_public_bytes = [0u8; 32];
+        inner.read_exact(&mut their_public_bytes).await?;
+        let their_public = PublicKey::from(their_public_bytes);
+
+        let shared_secret = secret.diffie_hellman(&their_public);
+
+        let derive_key = |label: &[u8]| -> [u8; 32] {
+            let mut hasher = Sha256::new();
+            hasher.update(shared_secret.as_bytes());
+            hasher.update(label);
+            hasher.finalize().into()
+        };
+        let low_to_high_key = derive_key(KEY_LABEL_LOW_TO_HIGH);
+        let high_to_low_key = derive_key(KEY_LABEL_HIGH_TO_LOW);
+
+        let (write_key, read_key) = if our_public.as_bytes() < &their_public_bytes {
+            (low_to_high_key, high_to_low_key)
+        } else {
+            (high_to_low_key, low_to_high_key)
+        };
+
+        Ok(EncryptedStream {
+            inner,
+            read_cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&read_key)),
+            write_cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&write_key)),
+            write_counter: 0,
+        })
+    }
+
+    /// Splits this stream into independent read and write halves so a
+    /// connection's response loop and its Pub/Sub push task can each hold
+    /// their own handle. Each half simply takes the read or write cipher it
+    /// already had; the nonce counter was already tracked per direction, so
+    /// splitting doesn't change the framing.
+    pub fn into_split(self) -> (EncryptedReadHalf<ReadHalf<S>>, EncryptedWriteHalf<WriteHalf<S>>) {
+        let (read_half, write_half) = io::split(self.inner);
+        (
+            EncryptedReadHalf {
+                inner: read_half,
+                cipher: self.read_cipher,
+            },
+            EncryptedWriteHalf {
+                inner: write_half,
+                cipher: self.write_cipher,
+                write_counter: self.write_counter,
+            },
+        )
+    }
+
+    /// Reads, decrypts and parses exactly one `RespValue` from the stream.
+    /// Returns `Ok(None)` once the peer has cleanly closed the connection
+    /// between frames.
+    pub async fn read_message(
+        &mut self,
+    ) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
+        read_encrypted_message(&mut self.inner, &self.read_cipher).await
+    }
+
+    /// Encrypts and writes `plaintext` (normally one `RespValue::to_bytes`,
+    /// or `to_bytes_buffered_for_version` for a `Stream` value) as a single
+    /// frame.
+    pub async fn write_message(
+        &mut self,
+        plaintext: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        write_encrypted_message(&mut self.inner, &self.write_cipher, &mut self.write_counter, plaintext).await
+    }
+}
+
+/// Read half produced by `EncryptedStream::into_split`.
+pub struct EncryptedReadHalf<R> {
+    inner: R,
+    cipher: Aes256Gcm,
+}
+
+impl<R: AsyncRead + Unpin> EncryptedReadHalf<R> {
+    pub async fn read_message(
+        &mut self,
+    ) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
+        read_encrypted_message(&mut self.inner, &self.cipher).await
+    }
+}
+
+/// Write half produced by `EncryptedStream::into_split`. Cheaply owned so it
+/// can be cloned-by-`Arc` into a Pub/Sub forwarding task alongside the
+/// connection's normal response loop.
+pub struct EncryptedWriteHalf<W> {
+    inner: W,
+    cipher: Aes256Gcm,
+    write_counter: u64,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptedWriteHalf<W> {
+    pub async fn write_message(
+        &mut self,
+        plaintext: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        write_encrypted_message(&mut self.inner, &self.cipher, &mut self.write_counter, plaintext).await
+    }
+}
+
+/// Builds the next outgoing nonce from a per-direction counter. Each
+/// direction counts independently so both sides can encrypt concurrently
+/// without ever reusing a nonce under the same key.
+fn next_write_nonce(counter: &mut u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter += 1;
+    nonce
+}
+
+async fn read_encrypted_message(
+    inner: &mut (impl AsyncRead + Unpin),
+    cipher: &Aes256Gcm,
+) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut nonce_bytes = [0u8; 12];
+    match inner.read_exact(&mut nonce_bytes).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut len_bytes = [0u8; 4];
+    inner.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+
+    let mut ciphertext = vec![0u8; len];
+    inner.read_exact(&mut ciphertext).await?;
+
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref())
+        .map_err(|_| "failed to decrypt encrypted transport frame")?;
+
+    // the whole frame is already decrypted into `plaintext` above, so there's
+    // nothing to gain from spilling an oversized value to disk here; use the
+    // same never-spill parse as the other buffered, message-oriented
+    // transports (WebSocket, relay — see `from_buffered_frames`)
+    let mut cursor = &plaintext[..];
+    RespValue::from_stream_buffered(&mut cursor).await
+}
+
+async fn write_encrypted_message(
+    inner: &mut (impl AsyncWrite + Unpin),
+    cipher: &Aes256Gcm,
+    write_counter: &mut u64,
+    plaintext: &[u8],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let nonce_bytes = next_write_nonce(write_counter);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+        .map_err(|_| "failed to encrypt encrypted transport frame")?;
+
+    inner.write_all(&nonce_bytes).await?;
+    inner
+        .write_all(&(ciphertext.len() as u32).to_be_bytes())
+        .await?;
+    inner.write_all(&ciphertext).await?;
+    inner.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use crate::protocol::{self, RespValue};
+use async_tungstenite::tokio::TokioAdapter;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A RESP connection carried over a WebSocket instead of a raw TCP byte
+/// stream. Each binary WebSocket message carries zero or more whole RESP
+/// frames; a frame may also be split across several WebSocket messages, so
+/// reads go through `protocol::from_buffered_frames` the same way the relay
+/// tunnel does.
+pub struct WsReadHalf<S> {
+    inner: SplitStream<WebSocketStream<TokioAdapter<S>>>,
+    buffer: Vec<u8>,
+}
+
+pub struct WsWriteHalf<S> {
+    inner: SplitSink<WebSocketStream<TokioAdapter<S>>, Message>,
+}
+
+pub fn split<S>(
+    stream: WebSocketStream<TokioAdapter<S>>,
+) -> (WsReadHalf<S>, WsWriteHalf<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sink, stream) = stream.split();
+    (
+        WsReadHalf {
+            inner: stream,
+            buffer: Vec::new(),
+        },
+        WsWriteHalf { inner: sink },
+    )
+}
+
+impl<S> WsReadHalf<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn read_message(
+        &mut self,
+    ) -> Result<Option<RespValue>, Box<dyn std::error::Error + Send + Sync>> {
+        let inner = &mut self.inner;
+        protocol::from_buffered_frames(&mut self.buffer, move || async {
+            loop {
+                match inner.next().await {
+                    Some(Ok(Message::Binary(bytes))) => return Ok(Some(bytes)),
+                    Some(Ok(Message::Close(_))) | None => return Ok(None),
+                    Some(Ok(_)) => continue, // ignore text/ping/pong/frame messages
+                    Some(Err(e)) => return Err(e.into()),
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl<S> WsWriteHalf<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn write_message(
+        &mut self,
+        bytes: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.send(Message::Binary(bytes)).await?;
+        Ok(())
+    }
+}